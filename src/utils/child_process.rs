@@ -1,18 +1,33 @@
 use std::collections::HashMap;
+use std::env;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, stderr};
 use std::iter::{Extend, FromIterator};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 use std::sync::{Arc, atomic::AtomicBool};
+use std::time::SystemTime;
 
+use serde::{Deserialize, Serialize};
 use xdg::BaseDirectories;
 
 use crate::errors::{Result, LeftError, LeftErrorKind};
 
-pub struct Nanny {}
+/// Field codes dropped from Exec: the file/URI codes (we never launch with
+/// files), plus `%i`, `%c`, `%k` and the deprecated codes.
+const DROPPED_FIELD_CODES: &[char] = &[
+    'f', 'F', 'u', 'U', 'i', 'c', 'k', 'd', 'D', 'n', 'N', 'v', 'm',
+];
+
+pub struct Nanny {
+    /// Name to match against `OnlyShowIn`/`NotShowIn`. `None` shows everything.
+    desktop_env: Option<String>,
+    /// Capture autostart/theme child stdout and stderr into log files.
+    log_output: bool,
+}
 
 impl Default for Nanny {
     fn default() -> Self {
@@ -22,97 +37,429 @@ impl Default for Nanny {
 
 impl Nanny {
     pub fn new() -> Nanny {
-        Nanny {}
+        Nanny { desktop_env: None, log_output: false }
+    }
+
+    /// Sets the desktop-environment name used to evaluate `OnlyShowIn`/`NotShowIn`.
+    pub fn with_desktop_env(mut self, desktop_env: impl Into<String>) -> Nanny {
+        self.desktop_env = Some(desktop_env.into());
+        self
+    }
+
+    /// Enables capturing autostart/theme child output into log files.
+    pub fn with_logging(mut self, log_output: bool) -> Nanny {
+        self.log_output = log_output;
+        self
     }
 
     pub fn autostart(&self) -> Children {
-        dirs::home_dir()
-            .map(|mut path| {
+        let mut children = Children::new();
+        let autostart_dir = match dirs::home_dir() {
+            Some(mut path) => {
                 path.push(".config");
                 path.push("autostart");
                 path
-            })
-            .and_then(|path| list_desktop_files(&path).ok())
-            .map(|files| {
-                files
-                    .iter()
-                    .filter_map(|file| boot_desktop_file(&file).ok())
-                    .collect::<Children>()
-            })
-            .unwrap_or_default()
-    }
-
-    pub fn boot_current_theme(&self) -> Result<Option<Child>> {
+            }
+            None => return children,
+        };
+        let files = match list_desktop_files(&autostart_dir) {
+            Ok(files) => files,
+            Err(_) => return children,
+        };
+
+        let mut cache = load_autostart_cache();
+        for file in &files {
+            let parsed = match parsed_entry_for(file, &mut cache) {
+                Ok(parsed) => parsed,
+                Err(_) => continue,
+            };
+            if let Ok((child, log_path)) = boot_parsed_entry(file, &parsed, self.desktop_env.as_deref(), self.log_output) {
+                children.insert_with_log(child, log_path);
+            }
+        }
+        cache.entries.retain(|path, _| files.contains(path));
+        save_autostart_cache(&cache);
+
+        children
+    }
+
+    pub fn boot_current_theme(&self) -> Result<Option<(GroupedChild, Option<PathBuf>)>> {
         let mut path = BaseDirectories::with_prefix("leftwm")?.create_config_directory("")?;
         path.push("themes");
         path.push("current");
         path.push("up");
         if path.is_file() {
-            Command::new(&path)
-                .stdin(Stdio::null())
-                .stdout(Stdio::null())
-                .spawn()
+            let mut command = Command::new(&path);
+            spawn_with_optional_log(&mut command, "theme-up", self.log_output)
                 .map(Some)
                 .map_err(|e| e.into())
         } else {
             Ok(None)
         }
     }
+
+    /// Runs the current theme's `down` script, waiting up to
+    /// `DOWN_SCRIPT_TIMEOUT` for it to exit before giving up on it.
+    pub fn shutdown_current_theme(&self) -> Result<()> {
+        let mut path = BaseDirectories::with_prefix("leftwm")?.create_config_directory("")?;
+        path.push("themes");
+        path.push("current");
+        path.push("down");
+        if path.is_file() {
+            let mut child = Command::new(&path).stdin(Stdio::null()).stdout(Stdio::null()).spawn()?;
+            wait_with_deadline(&mut child, DOWN_SCRIPT_TIMEOUT)?;
+            Ok(())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Runs the current theme's `down` script, then kills every tracked process group.
+pub fn shutdown(nanny: &Nanny, children: &mut Children) -> Result<()> {
+    // kill_all must run even if the down script fails, or it leaks process groups
+    let result = nanny.shutdown_current_theme();
+    children.kill_all();
+    result
 }
 
-fn boot_desktop_file(path: &PathBuf) -> io::Result<Child> {
-    let entries = parse_desktop_file(path)?;
-    // let entries = match parse_desktop_file(path) {
-    //     Ok(entries) => entries,
-    //     Err(err) => return Err(err)
-    // };
+const DOWN_SCRIPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const DOWN_SCRIPT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
 
-    if let Some(hidden) = entries.get("Hidden") {
-        if hidden == "true" {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "hidden desktop file")); // hack
+/// Waits for `child` to exit, polling instead of blocking outright so a hung
+/// `down` script can't stall shutdown past `timeout`; kills it and gives up
+/// if it's still running once the deadline passes.
+fn wait_with_deadline(child: &mut Child, timeout: std::time::Duration) -> io::Result<()> {
+    let start = std::time::Instant::now();
+    loop {
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "down script did not exit before the shutdown deadline"));
         }
+        std::thread::sleep(DOWN_SCRIPT_POLL_INTERVAL);
     }
-    // TODO: if TERMINAL is set to true then find users default terminal-emulator and execute within
-    let args = match entries.get("Exec") {
-        Some(exec) => sanitize_exec(exec),
-        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "could not find Exec key")), // hack
+}
+
+/// The subset of Desktop Entry keys we act on, from the `[Desktop Entry]` group.
+#[derive(Debug, Default, Clone)]
+struct DesktopEntry {
+    exec: Option<String>,
+    try_exec: Option<String>,
+    hidden: bool,
+    no_display: bool,
+    only_show_in: Vec<String>,
+    not_show_in: Vec<String>,
+}
+
+/// The cacheable result of parsing a `.desktop` file: its argv plus the
+/// fields needed to decide whether to launch it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ParsedEntry {
+    argv: Vec<String>,
+    try_exec: Option<String>,
+    hidden: bool,
+    no_display: bool,
+    only_show_in: Vec<String>,
+    not_show_in: Vec<String>,
+}
+
+impl ParsedEntry {
+    fn from_desktop_entry(entry: DesktopEntry) -> io::Result<ParsedEntry> {
+        let exec = entry
+            .exec
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not find Exec key"))?;
+        let argv = exec_to_argv(exec).map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+        Ok(ParsedEntry {
+            argv,
+            try_exec: entry.try_exec,
+            hidden: entry.hidden,
+            no_display: entry.no_display,
+            only_show_in: entry.only_show_in,
+            not_show_in: entry.not_show_in,
+        })
+    }
+}
+
+/// On-disk cache of parsed autostart entries, keyed by path and mtime.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AutostartCache {
+    entries: HashMap<PathBuf, (SystemTime, ParsedEntry)>,
+}
+
+/// Loads the autostart cache, or an empty one if missing/unreadable/malformed.
+fn load_autostart_cache() -> AutostartCache {
+    autostart_cache_path()
+        .ok()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Persists the autostart cache, best-effort.
+fn save_autostart_cache(cache: &AutostartCache) {
+    let path = match autostart_cache_path() {
+        Ok(path) => path,
+        Err(_) => return,
     };
-    // // from: https://askubuntu.com/questions/5172/running-a-desktop-file-in-the-terminal
-    //let args = format!("`grep '^Exec' {:?} | tail -1 | sed 's/^Exec=//' | sed 's/%.//' | sed 's/^\"//g' | sed 's/\" *$//g'`", path);
-    Command::new("sh").arg("-c").arg(args).spawn()
+    if let Ok(bytes) = bincode::serialize(cache) {
+        let _ = fs::write(path, bytes);
+    }
 }
 
-fn sanitize_exec(exec: &String) -> String {
-    // TODO: sanitize command -> e.g. remove %U, un-escape stuff,
-    //  https://developer.gnome.org/desktop-entry-spec/#exec-variables
-    // TODO
-    format!("`echo \"{}\" | sed 's/%.//' | sed 's/^\\\"//g' | sed 's/\\\" *$//g'`", exec)
+fn autostart_cache_path() -> io::Result<PathBuf> {
+    BaseDirectories::with_prefix("leftwm")
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+        .place_cache_file("autostart.cache")
 }
 
-// reads desktop file from path and return its entries as key-value pairs in a HashMap.
+/// Returns the `ParsedEntry` for `path`, reusing `cache` while mtime matches.
+fn parsed_entry_for(path: &PathBuf, cache: &mut AutostartCache) -> io::Result<ParsedEntry> {
+    let mtime = fs::metadata(path)?.modified()?;
+    if let Some((cached_mtime, parsed)) = cache.entries.get(path) {
+        if *cached_mtime == mtime {
+            return Ok(parsed.clone());
+        }
+    }
+
+    let parsed = ParsedEntry::from_desktop_entry(parse_desktop_file(path)?)?;
+    cache.entries.insert(path.clone(), (mtime, parsed.clone()));
+    Ok(parsed)
+}
+
+fn boot_parsed_entry(
+    path: &Path,
+    entry: &ParsedEntry,
+    desktop_env: Option<&str>,
+    log_output: bool,
+) -> io::Result<(GroupedChild, Option<PathBuf>)> {
+    if entry.hidden || entry.no_display {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "desktop entry is hidden"));
+    }
+
+    if let Some(de) = desktop_env {
+        if !entry.only_show_in.is_empty() && !entry.only_show_in.iter().any(|shown| shown == de) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "desktop entry is not listed in OnlyShowIn for this environment",
+            ));
+        }
+        if entry.not_show_in.iter().any(|hidden| hidden == de) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "desktop entry is listed in NotShowIn for this environment",
+            ));
+        }
+    }
+
+    if let Some(try_exec) = &entry.try_exec {
+        if find_on_path(try_exec).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("TryExec target `{}` not found on PATH", try_exec),
+            ));
+        }
+    }
+
+    let (program, args) = entry
+        .argv
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Exec key expanded to no arguments"))?;
+
+    let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("autostart-entry");
+    let mut command = Command::new(program);
+    command.args(args);
+    spawn_with_optional_log(&mut command, name, log_output)
+}
+
+/// A `Child` known to lead its own process group; only `spawn_in_new_process_group`
+/// can make one, which is what lets `Children::insert_with_log` trust its pgid.
+pub struct GroupedChild(Child);
+
+/// Spawns `command` in a new session/process group so the whole subtree it
+/// forks can later be torn down with `Children::kill_all`.
+fn spawn_in_new_process_group(command: &mut Command) -> io::Result<GroupedChild> {
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn().map(GroupedChild)
+}
+
+/// Spawns `command` with its stdin closed. When `log_output` is set, stdout
+/// and stderr are redirected into `<name>.log`; otherwise they're discarded.
+fn spawn_with_optional_log(
+    command: &mut Command,
+    name: &str,
+    log_output: bool,
+) -> io::Result<(GroupedChild, Option<PathBuf>)> {
+    command.stdin(Stdio::null());
+    if log_output {
+        let (log_file, log_path) = open_entry_log(name)?;
+        let err_file = log_file.try_clone()?;
+        command.stdout(Stdio::from(log_file)).stderr(Stdio::from(err_file));
+        let child = spawn_in_new_process_group(command)?;
+        Ok((child, Some(log_path)))
+    } else {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+        let child = spawn_in_new_process_group(command)?;
+        Ok((child, None))
+    }
+}
+
+/// Opens (creating if needed) `$XDG_CACHE_HOME/leftwm/logs/<name>.log` for append.
+fn open_entry_log(name: &str) -> io::Result<(File, PathBuf)> {
+    let base = BaseDirectories::with_prefix("leftwm")
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let dir = base.create_cache_directory("logs")?;
+    let path = dir.join(format!("{}.log", name));
+    let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+    Ok((file, path))
+}
+
+// tokenizes an Exec value into an argv per the Desktop Entry spec: splits on
+// unquoted whitespace, honours double-quoted arguments and their escapes,
+// and expands field codes (%% -> %, DROPPED_FIELD_CODES dropped, else an error).
+fn exec_to_argv(exec: &str) -> std::result::Result<Vec<String>, String> {
+    let mut argv = Vec::new();
+    let mut current = String::new();
+    let mut in_current = false;
+    let mut in_quotes = false;
+    let mut chars = exec.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => match chars.next() {
+                Some('%') => {
+                    current.push('%');
+                    in_current = true;
+                }
+                Some(code) if DROPPED_FIELD_CODES.contains(&code) => {}
+                Some(code) => return Err(format!("unsupported field code `%{}`", code)),
+                None => return Err("Exec value ends with a dangling `%`".to_string()),
+            },
+            '"' if in_quotes => in_quotes = false,
+            '"' if !in_quotes => {
+                in_quotes = true;
+                in_current = true;
+            }
+            '\\' if in_quotes => match chars.next() {
+                Some(next @ ('"' | '`' | '$' | '\\')) => {
+                    current.push(next);
+                    in_current = true;
+                }
+                Some(next) => {
+                    current.push('\\');
+                    current.push(next);
+                    in_current = true;
+                }
+                None => return Err("Exec value ends with a dangling `\\`".to_string()),
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if in_current {
+                    argv.push(std::mem::take(&mut current));
+                    in_current = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_current = true;
+            }
+        }
+    }
+
+    if in_quotes {
+        return Err("Exec value has an unterminated quoted argument".to_string());
+    }
+    if in_current {
+        argv.push(current);
+    }
+
+    Ok(argv)
+}
+
+/// Looks for an executable named `name` in `PATH`, the way a shell would.
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return is_executable_file(Path::new(name)).then(|| PathBuf::from(name));
+    }
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable_file(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable_file(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable_file(path: &Path) -> bool {
+    path.is_file()
+}
+
+// reads desktop file from path and returns the parsed `[Desktop Entry]` group.
 // if a key exists multiple times the last value is finally used.
-fn parse_desktop_file(path: &PathBuf) -> io::Result<HashMap<String, String>> {
-    let mut entries = HashMap::new();
+fn parse_desktop_file(path: &PathBuf) -> io::Result<DesktopEntry> {
+    let mut entry = DesktopEntry::default();
+    let mut in_desktop_entry_group = false;
     let lines = read_lines(path)?;
     for line in lines {
         if let Ok(line) = line {
             // remove trailing newlines and filter comments and empty lines
             let line = line.trim();
-            if line.starts_with("#") || line == "" {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                in_desktop_entry_group = line == "[Desktop Entry]";
+                continue;
+            }
+
+            if !in_desktop_entry_group {
                 continue;
             }
 
             // split line into key-value pairs with the first "=" as a separator
             let mut splitter = line.splitn(2, '=');
-            let key = splitter.next().unwrap_or_default();
-            let value = splitter.next().unwrap_or_default();
+            let key = splitter.next().unwrap_or_default().trim();
+            let value = splitter.next().unwrap_or_default().trim();
 
-            if key != "" {
-                entries.insert(String::from(key), String::from(value));
+            match key {
+                "Exec" => entry.exec = Some(value.to_string()),
+                "TryExec" => entry.try_exec = Some(value.to_string()),
+                "Hidden" => entry.hidden = value == "true",
+                "NoDisplay" => entry.no_display = value == "true",
+                "OnlyShowIn" => entry.only_show_in = split_entry_list(value),
+                "NotShowIn" => entry.not_show_in = split_entry_list(value),
+                _ => {}
             }
         }
     }
-    Ok(entries)
+    Ok(entry)
+}
+
+/// Splits a semicolon-separated Desktop Entry list value (e.g. `OnlyShowIn`).
+fn split_entry_list(value: &str) -> Vec<String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
 }
 
 // reads a file and returns iterator over its lines
@@ -140,6 +487,15 @@ fn list_desktop_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
     Ok(list)
 }
 
+/// A tracked child together with the id of the process group it leads and,
+/// if output capture was enabled for it, the path of its log file.
+#[derive(Debug)]
+struct ChildEntry {
+    child: Child,
+    pgid: i32,
+    log_path: Option<PathBuf>,
+}
+
 /// A struct managing children processes.
 ///
 /// The `reap` method could be called at any place the user wants to.
@@ -147,7 +503,7 @@ fn list_desktop_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
 /// flag to do a epoch-based reaping.
 #[derive(Debug, Default)]
 pub struct Children {
-    inner: HashMap<u32, Child>,
+    inner: HashMap<u32, ChildEntry>,
 }
 
 impl Children {
@@ -163,9 +519,17 @@ impl Children {
     /// Insert a `Child` in the `Children`.
     /// If this `Children` did not have this value present, true is returned.
     /// If this `Children` did have this value present, false is returned.
-    pub fn insert(&mut self, child: Child) -> bool {
+    pub fn insert(&mut self, child: GroupedChild) -> bool {
+        self.insert_with_log(child, None)
+    }
+    /// Like `insert`, also recording the path of its captured log file, if any.
+    pub fn insert_with_log(&mut self, child: GroupedChild, log_path: Option<PathBuf>) -> bool {
         // Not possible to have duplication!
-        self.inner.insert(child.id(), child).is_none()
+        let child = child.0;
+        let pid = child.id();
+        self.inner
+            .insert(pid, ChildEntry { child, pgid: pid as i32, log_path })
+            .is_none()
     }
     /// Merge another `Children` into this `Children`.
     pub fn merge(&mut self, reaper: Children) {
@@ -174,27 +538,98 @@ impl Children {
     /// Try reaping all the children processes managed by this struct.
     pub fn reap(&mut self) {
         // The `try_wait` needs `child` to be `mut`, but only `HashMap::retain`
-        // allows modifying the value. Here `id` is not needed.
-        self.inner
-            .retain(|_, child| child.try_wait().map_or(true, |ret| ret.is_none()))
+        // allows modifying the value. Here `id` is not needed. Since every
+        // tracked child is its own process group leader, this also reaps the
+        // group leader specifically, not just an arbitrary descendant.
+        self.inner.retain(|_, entry| match entry.child.try_wait() {
+            Ok(Some(status)) => {
+                if !status.success() {
+                    match &entry.log_path {
+                        Some(path) => log::warn!(
+                            "autostart process {} exited with {}, see {}",
+                            entry.pgid,
+                            status,
+                            path.display()
+                        ),
+                        None => log::warn!("autostart process {} exited with {}", entry.pgid, status),
+                    }
+                }
+                false
+            }
+            Ok(None) => true,
+            // Keep the entry and retry later, matching `try_wait`'s previous
+            // `map_or(true, ...)` handling; an error here doesn't mean the
+            // process is actually gone.
+            Err(_) => true,
+        })
+    }
+    /// Terminates every tracked process group: `SIGTERM`, then `SIGKILL` for
+    /// anything still alive after a short grace period.
+    pub fn kill_all(&mut self) {
+        let pgids: Vec<i32> = self.inner.values().map(|entry| entry.pgid).collect();
+        for pgid in &pgids {
+            unsafe {
+                libc::killpg(*pgid, libc::SIGTERM);
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        self.reap();
+
+        // Every group gets SIGKILL, including those whose leader already
+        // exited, so descendants that ignored SIGTERM are not left behind.
+        for pgid in &pgids {
+            unsafe {
+                libc::killpg(*pgid, libc::SIGKILL);
+            }
+        }
+        // A process group leader can outlive SIGKILL (stuck in uninterruptible
+        // I/O). Poll with a bound instead of spinning forever, so a stuck leader
+        // can't hang the whole shutdown sequence.
+        const REAP_ATTEMPTS: u32 = 25;
+        const REAP_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+        for _ in 0..REAP_ATTEMPTS {
+            if self.inner.is_empty() {
+                break;
+            }
+            self.reap();
+            if self.inner.is_empty() {
+                break;
+            }
+            std::thread::sleep(REAP_INTERVAL);
+        }
+        if !self.inner.is_empty() {
+            log::warn!(
+                "giving up waiting on {} process group(s) after SIGKILL: {:?}",
+                self.inner.len(),
+                self.inner.values().map(|entry| entry.pgid).collect::<Vec<_>>()
+            );
+            self.inner.clear();
+        }
     }
 }
 
-impl FromIterator<Child> for Children {
-    fn from_iter<T: IntoIterator<Item=Child>>(iter: T) -> Self {
+impl FromIterator<GroupedChild> for Children {
+    fn from_iter<T: IntoIterator<Item=GroupedChild>>(iter: T) -> Self {
         Self {
             inner: iter
                 .into_iter()
-                .map(|child| (child.id(), child))
+                .map(|child| {
+                    let child = child.0;
+                    let pgid = child.id() as i32;
+                    (child.id(), ChildEntry { child, pgid, log_path: None })
+                })
                 .collect::<HashMap<_, _>>(),
         }
     }
 }
 
-impl Extend<Child> for Children {
-    fn extend<T: IntoIterator<Item=Child>>(&mut self, iter: T) {
-        self.inner
-            .extend(iter.into_iter().map(|child| (child.id(), child)))
+impl Extend<GroupedChild> for Children {
+    fn extend<T: IntoIterator<Item=GroupedChild>>(&mut self, iter: T) {
+        self.inner.extend(iter.into_iter().map(|child| {
+            let child = child.0;
+            let pgid = child.id() as i32;
+            (child.id(), ChildEntry { child, pgid, log_path: None })
+        }))
     }
 }
 
@@ -204,3 +639,188 @@ pub fn register_child_hook(flag: Arc<AtomicBool>) {
     let _ = signal_hook::flag::register(signal_hook::SIGCHLD, flag)
         .map_err(|err| log::error!("Cannot register SIGCHLD signal handler: {:?}", err));
 }
+
+/// Register `SIGTERM`/`SIGINT`/`SIGHUP` signal handlers. Once any is
+/// received, the flag will be set true. User should run `shutdown` then exit.
+pub fn register_shutdown_hook(flag: Arc<AtomicBool>) {
+    for signal in &[signal_hook::SIGTERM, signal_hook::SIGINT, signal_hook::SIGHUP] {
+        let _ = signal_hook::flag::register(*signal, flag.clone())
+            .map_err(|err| log::error!("Cannot register shutdown signal handler: {:?}", err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_to_argv_splits_unquoted_whitespace() {
+        assert_eq!(exec_to_argv("firefox --new-window").unwrap(), vec!["firefox", "--new-window"]);
+    }
+
+    #[test]
+    fn exec_to_argv_keeps_quoted_argument_together() {
+        assert_eq!(
+            exec_to_argv(r#"sh -c "echo hello world""#).unwrap(),
+            vec!["sh", "-c", "echo hello world"]
+        );
+    }
+
+    #[test]
+    fn exec_to_argv_unescapes_special_chars_in_quotes() {
+        assert_eq!(
+            exec_to_argv(r#"sh -c "a \" b \` c \$ d \\ e""#).unwrap(),
+            vec!["sh", "-c", r#"a " b ` c $ d \ e"#]
+        );
+    }
+
+    #[test]
+    fn exec_to_argv_passes_through_unrecognised_escape() {
+        // `\n` is not one of the escapable characters inside quotes, so the
+        // backslash is kept literally rather than being stripped.
+        assert_eq!(exec_to_argv(r#"sh -c "a \n b""#).unwrap(), vec!["sh", "-c", r"a \n b"]);
+    }
+
+    #[test]
+    fn exec_to_argv_expands_percent_percent_to_literal_percent() {
+        assert_eq!(exec_to_argv("echo 100%%").unwrap(), vec!["echo", "100%"]);
+    }
+
+    #[test]
+    fn exec_to_argv_drops_file_and_uri_field_codes() {
+        assert_eq!(exec_to_argv("app %f %U --flag").unwrap(), vec!["app", "--flag"]);
+    }
+
+    #[test]
+    fn exec_to_argv_rejects_unsupported_field_code() {
+        assert!(exec_to_argv("app %z").is_err());
+    }
+
+    #[test]
+    fn exec_to_argv_rejects_dangling_percent() {
+        assert!(exec_to_argv("app %").is_err());
+    }
+
+    #[test]
+    fn exec_to_argv_rejects_dangling_backslash() {
+        assert!(exec_to_argv(r#"sh -c "a \"#).is_err());
+    }
+
+    #[test]
+    fn exec_to_argv_rejects_unterminated_quote() {
+        assert!(exec_to_argv(r#"sh -c "echo hello"#).is_err());
+    }
+
+    fn unique_temp_path(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("leftwm-test-{}-{}-{}.desktop", std::process::id(), name, line!()));
+        path
+    }
+
+    #[test]
+    fn parsed_entry_for_reuses_cache_while_mtime_is_unchanged() {
+        let path = unique_temp_path("cache-hit");
+        fs::write(&path, "[Desktop Entry]\nExec=first\n").unwrap();
+
+        let mut cache = AutostartCache::default();
+        let first = parsed_entry_for(&path, &mut cache).unwrap();
+        assert_eq!(first.argv, vec!["first"]);
+
+        // Swap the cached argv for a sentinel without touching the file: a
+        // cache hit returns the sentinel, while a reparse would return "first".
+        let cached_mtime = cache.entries.get(&path).unwrap().0;
+        cache.entries.get_mut(&path).unwrap().1.argv = vec!["cached".to_string()];
+        let second = parsed_entry_for(&path, &mut cache).unwrap();
+        assert_eq!(second.argv, vec!["cached"]);
+        assert_eq!(cache.entries.get(&path).unwrap().0, cached_mtime);
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn parsed_entry_for_reparses_when_mtime_changes() {
+        let path = unique_temp_path("cache-stale");
+        fs::write(&path, "[Desktop Entry]\nExec=first\n").unwrap();
+
+        let mut cache = AutostartCache::default();
+        let first = parsed_entry_for(&path, &mut cache).unwrap();
+        assert_eq!(first.argv, vec!["first"]);
+
+        // Give the filesystem time to observe a distinct mtime on rewrite.
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        fs::write(&path, "[Desktop Entry]\nExec=second\n").unwrap();
+
+        let second = parsed_entry_for(&path, &mut cache).unwrap();
+        assert_eq!(second.argv, vec!["second"]);
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn kill_all_reaps_promptly_even_when_sigterm_is_ignored() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("trap '' TERM; sleep 30");
+        let child = spawn_in_new_process_group(&mut command).unwrap();
+        let mut children = Children::new();
+        children.insert(child);
+        assert_eq!(children.len(), 1);
+
+        let start = std::time::Instant::now();
+        children.kill_all();
+        assert!(children.is_empty());
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    // Guards mutation of process-global env vars so tests that need to set
+    // one (there's only `XDG_CONFIG_HOME` below) don't race each other.
+    static XDG_ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    // Sets an env var for the guard's lifetime and restores it on drop, even
+    // if the test panics, so a failed assertion can't leak state to later tests.
+    struct EnvVarGuard {
+        key: &'static str,
+        previous: Option<std::ffi::OsString>,
+    }
+
+    impl EnvVarGuard {
+        fn set(key: &'static str, value: impl AsRef<std::ffi::OsStr>) -> EnvVarGuard {
+            let previous = env::var_os(key);
+            env::set_var(key, value);
+            EnvVarGuard { key, previous }
+        }
+    }
+
+    impl Drop for EnvVarGuard {
+        fn drop(&mut self) {
+            match &self.previous {
+                Some(value) => env::set_var(self.key, value),
+                None => env::remove_var(self.key),
+            }
+        }
+    }
+
+    #[test]
+    fn shutdown_runs_kill_all_even_when_theme_down_script_fails() {
+        let _env_lock = XDG_ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Point XDG_CONFIG_HOME at a plain file so `shutdown_current_theme`
+        // can't even create its config directory and returns `Err`.
+        let home = unique_temp_path("shutdown-down-err");
+        fs::write(&home, "not a directory").unwrap();
+        let _env_guard = EnvVarGuard::set("XDG_CONFIG_HOME", &home);
+
+        let nanny = Nanny::new();
+        assert!(nanny.shutdown_current_theme().is_err());
+
+        let mut command = Command::new("sleep");
+        command.arg("30");
+        let child = spawn_in_new_process_group(&mut command).unwrap();
+        let mut children = Children::new();
+        children.insert(child);
+
+        assert!(shutdown(&nanny, &mut children).is_err());
+        assert!(children.is_empty());
+
+        fs::remove_file(&home).ok();
+    }
+}